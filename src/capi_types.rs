@@ -0,0 +1,55 @@
+use std::os::raw::{c_char, c_int, c_void};
+
+use anyhow::Error;
+
+use crate::convert_error_to_cstring;
+
+/// Opaque handle for the backup task C API.
+#[repr(C)]
+pub struct ProxmoxBackupHandle { _private: [u8; 0] }
+
+/// Opaque handle for the restore C API.
+#[repr(C)]
+pub struct ProxmoxRestoreHandle { _private: [u8; 0] }
+
+/// Wrapper to make a borrowed data pointer `Send` so it can be moved into
+/// an async task.
+///
+/// The caller is responsible for keeping the buffer valid until the
+/// corresponding callback fires.
+pub(crate) struct DataPointer(pub *const u8);
+unsafe impl Send for DataPointer {}
+
+/// Like [`DataPointer`], but for an output buffer the async task writes
+/// into (e.g. a result array or an out-parameter).
+///
+/// The caller is responsible for keeping the buffer valid and large
+/// enough until the corresponding callback fires.
+pub(crate) struct MutDataPointer<T>(pub *mut T);
+unsafe impl<T> Send for MutDataPointer<T> {}
+
+/// Bundles the pieces needed to deliver the result of an async operation
+/// back across the C boundary.
+pub(crate) struct CallbackPointers {
+    pub callback: extern "C" fn(*mut c_void),
+    pub callback_data: *mut c_void,
+    pub error: *mut *mut c_char,
+    pub result: *mut c_int,
+}
+unsafe impl Send for CallbackPointers {}
+
+impl CallbackPointers {
+    /// Store `result` (or the error) at the pointers given to us by the
+    /// caller, then invoke the completion callback.
+    pub fn send_result(self, result: Result<c_int, Error>) {
+        match result {
+            Ok(value) => unsafe { *self.result = value; },
+            Err(err) => {
+                unsafe { *self.result = -1; }
+                let errmsg = convert_error_to_cstring(err.to_string());
+                unsafe { *self.error = errmsg.into_raw(); }
+            }
+        }
+        (self.callback)(self.callback_data);
+    }
+}
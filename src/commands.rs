@@ -0,0 +1,14 @@
+/// An operation appended to an open image's fixed index.
+///
+/// `UploadQueue::write` turns each incoming buffer into one of these,
+/// uploading the chunk data first where needed, and then appends it to
+/// the image's index writer.
+pub(crate) enum ImageCommand {
+    /// Store freshly uploaded chunk data at `offset`.
+    AppendChunk { offset: u64, digest: [u8; 32], data: Vec<u8> },
+    /// Mark `offset` as a run of zeroes without allocating a chunk.
+    AppendZero { offset: u64, size: u64 },
+    /// Reuse a chunk the server already has for the previous backup's
+    /// image at `offset`, without uploading any data.
+    KnownChunk { offset: u64, digest: [u8; 32] },
+}
@@ -24,6 +24,8 @@ use restore::*;
 
 mod tools;
 
+mod logger;
+
 pub const PROXMOX_BACKUP_DEFAULT_CHUNK_SIZE: u64 = 1024*1024*4;
 
 /// Free returned error messages
@@ -40,7 +42,7 @@ pub extern "C" fn proxmox_backup_free_error(ptr: * mut c_char) {
 }
 
 // Note: UTF8 Strings may contain 0 bytes.
-fn convert_error_to_cstring(err: String) -> CString {
+pub(crate) fn convert_error_to_cstring(err: String) -> CString {
     match CString::new(err) {
         Ok(msg) => msg,
         Err(err) => {
@@ -132,6 +134,23 @@ impl GotResultCondition {
 }
 
 
+/// Install a log/progress callback
+///
+/// Installs, once, a `tracing` layer that forwards every event emitted
+/// by this crate and the underlying `pbs-client` code to `callback`.
+/// `level` is one of 0 (error), 1 (warn), 2 (info), 3 (debug) or 4
+/// (trace). The callback may be invoked from any worker thread that
+/// produced the event, so it must be thread-safe. Only the first call
+/// has any effect.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn proxmox_backup_set_log_callback(
+    callback: extern "C" fn(level: c_int, msg: *const c_char, data: *mut c_void),
+    data: *mut c_void,
+) {
+    logger::set_log_callback(callback, data);
+}
+
 /// Create a new instance
 ///
 /// Uses `PROXMOX_BACKUP_DEFAULT_CHUNK_SIZE` if `chunk_size` is zero.
@@ -319,6 +338,98 @@ pub extern "C" fn proxmox_backup_register_image_async(
     });
 }
 
+/// Fetch the previous backup's known chunk digests for an image
+///
+/// Copies up to `*out_len` 32-byte digests (in offset order, one per
+/// chunk of the previous backup's image) into `out_digests`, then sets
+/// `*out_len` to the number actually available. Pass `out_digests ==
+/// NULL` to just query the count first. `dev_id` must have been
+/// registered with `incremental = true`; if no previous backup existed,
+/// `*out_len` is set to 0.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn proxmox_backup_get_known_chunks(
+    handle: *mut ProxmoxBackupHandle,
+    dev_id: u8,
+    out_digests: *mut c_uchar,
+    out_len: *mut u64,
+    error: * mut * mut c_char,
+) -> c_int {
+    let task = unsafe { &mut *(handle as * mut BackupTask) };
+
+    let mut result: c_int = -1;
+
+    let mut got_result_condition = GotResultCondition::new();
+
+    let callback_info = got_result_condition.callback_info(&mut result, error);
+
+    let capacity = unsafe { *out_len };
+    let out_digests = MutDataPointer(out_digests);
+    let out_len = MutDataPointer(out_len);
+
+    task.runtime().spawn(async move {
+        let result = task.get_known_chunks(dev_id).await
+            .map(|digests| copy_known_chunks(digests, out_digests, out_len, capacity));
+        callback_info.send_result(result);
+    });
+
+    got_result_condition.wait();
+
+    result
+}
+
+/// Fetch the previous backup's known chunk digests for an image (async)
+///
+/// Like `proxmox_backup_get_known_chunks`, but runs on the task's own
+/// runtime and delivers the result via `callback` instead of blocking
+/// the calling thread.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn proxmox_backup_get_known_chunks_async(
+    handle: *mut ProxmoxBackupHandle,
+    dev_id: u8,
+    out_digests: *mut c_uchar,
+    out_len: *mut u64,
+    callback: extern "C" fn(*mut c_void),
+    callback_data: *mut c_void,
+    result: *mut c_int,
+    error: * mut * mut c_char,
+) {
+    let task = unsafe { &mut *(handle as * mut BackupTask) };
+    let callback_info = CallbackPointers { callback, callback_data, error, result };
+
+    let capacity = unsafe { *out_len };
+    let out_digests = MutDataPointer(out_digests);
+    let out_len = MutDataPointer(out_len);
+
+    task.runtime().spawn(async move {
+        let result = task.get_known_chunks(dev_id).await
+            .map(|digests| copy_known_chunks(digests, out_digests, out_len, capacity));
+        callback_info.send_result(result);
+    });
+}
+
+/// Copy up to `capacity` digests into `out_digests` (if non-NULL) and
+/// store the total count in `*out_len`, for the sync and async
+/// `proxmox_backup_get_known_chunks` variants.
+fn copy_known_chunks(
+    digests: Vec<[u8; 32]>,
+    out_digests: MutDataPointer<c_uchar>,
+    out_len: MutDataPointer<u64>,
+    capacity: u64,
+) -> c_int {
+    if !out_digests.0.is_null() {
+        let n = digests.len().min(capacity as usize);
+        for (i, digest) in digests.iter().take(n).enumerate() {
+            unsafe {
+                std::ptr::copy_nonoverlapping(digest.as_ptr(), out_digests.0.add(i * 32), 32);
+            }
+        }
+    }
+    unsafe { *out_len.0 = digests.len() as u64; }
+    0
+}
+
 /// Add a configuration blob to the backup (sync)
 #[no_mangle]
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
@@ -594,7 +705,10 @@ pub extern "C" fn proxmox_restore_connect(
             fingerprint,
         };
 
-        ProxmoxRestore::new(setup)
+        let conn = ProxmoxRestore::new(setup)?;
+        conn.runtime().block_on(conn.connect())?;
+
+        Ok(conn)
     });
 
     match result {
@@ -606,6 +720,106 @@ pub extern "C" fn proxmox_restore_connect(
     }
 }
 
+/// Open connection to the backup server (async)
+///
+/// Builds the `ProxmoxRestore` instance and returns its handle via
+/// `*handle` immediately, while the connection itself is established on
+/// the instance's own runtime; `callback` fires once `connect()`
+/// completes (or fails).
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn proxmox_restore_connect_async(
+    repo: *const c_char,
+    snapshot: *const c_char,
+    password: *const c_char,
+    keyfile: *const c_char,
+    key_password: *const c_char,
+    fingerprint: *const c_char,
+    callback: extern "C" fn(*mut c_void),
+    callback_data: *mut c_void,
+    handle: *mut *mut ProxmoxRestoreHandle,
+    result: *mut c_int,
+    error: * mut * mut c_char,
+) {
+    let setup: Result<_, Error> = try_block!({
+        let repo: BackupRepository = tools::utf8_c_string(repo)?
+            .ok_or_else(|| format_err!("repo must not be NULL"))?
+            .parse()?;
+
+        let snapshot: BackupDir = tools::utf8_c_string_lossy(snapshot)
+            .ok_or_else(|| format_err!("snapshot must not be NULL"))?
+            .parse()?;
+
+        let backup_type = snapshot.group().backup_type();
+        let backup_id = snapshot.group().backup_id().to_owned();
+        let backup_time = snapshot.backup_time();
+
+        if backup_type != "vm" {
+            bail!("wrong backup type ({} != vm)", backup_type);
+        }
+
+        let password = tools::utf8_c_string(password)?;
+        let keyfile = tools::utf8_c_string(keyfile)?.map(std::path::PathBuf::from);
+        let key_password = tools::utf8_c_string(key_password)?;
+        let fingerprint = tools::utf8_c_string(fingerprint)?;
+
+        Ok(BackupSetup {
+            host: repo.host().to_owned(),
+            user: repo.user().to_owned(),
+            store: repo.store().to_owned(),
+            chunk_size: PROXMOX_BACKUP_DEFAULT_CHUNK_SIZE, // not used by restore
+            backup_id,
+            password,
+            backup_time,
+            keyfile,
+            key_password,
+            fingerprint,
+        })
+    });
+
+    let conn = match setup.and_then(ProxmoxRestore::new) {
+        Ok(conn) => conn,
+        Err(err) => {
+            unsafe { *handle = ptr::null_mut(); }
+            let errmsg = convert_error_to_cstring(err.to_string());
+            unsafe { *error = errmsg.into_raw(); }
+            unsafe { *result = -1; }
+            callback(callback_data);
+            return;
+        }
+    };
+
+    let conn = Box::into_raw(Box::new(conn)) as * mut ProxmoxRestoreHandle;
+    unsafe { *handle = conn; }
+
+    let conn = unsafe { &mut *(conn as * mut ProxmoxRestore) };
+    let callback_info = CallbackPointers { callback, callback_data, error, result };
+
+    conn.runtime().spawn(async move {
+        let result = conn.connect().await;
+        callback_info.send_result(result);
+    });
+}
+
+/// Abort a running restore task
+///
+/// This marks the restore as aborted: later connect or image download
+/// calls fail, and an in-flight image download notices within about one
+/// chunk; a connect already in progress is not interrupted. It is
+/// still necessary to call proxmox_restore_disconnect() to close the
+/// connection and free allocated memory.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn proxmox_restore_abort(
+    handle: *mut ProxmoxRestoreHandle,
+    reason: *const c_char,
+) {
+    let conn = unsafe { &mut *(handle as * mut ProxmoxRestore) };
+
+    let reason = unsafe { tools::utf8_c_string_lossy_non_null(reason) };
+    conn.abort(reason);
+}
+
 /// Disconnect and free allocated memory
 ///
 /// The handle becomes invalid after this call.
@@ -657,3 +871,139 @@ pub extern "C" fn proxmox_restore_image(
 
     0
 }
+
+/// Open an archive for random-access reads.
+///
+/// Returns an opaque `aid` to pass to `proxmox_restore_read_image_at`.
+/// `cache_chunks` bounds the image's in-memory LRU chunk cache; pass `0`
+/// to use a sensible default.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn proxmox_restore_open_image(
+    handle: *mut ProxmoxRestoreHandle,
+    archive_name: *const c_char, // expect full name here, i.e. "name.img.fidx"
+    cache_chunks: u64,
+    error: * mut * mut c_char,
+) -> c_int {
+
+    let conn = unsafe { &mut *(handle as * mut ProxmoxRestore) };
+
+    let result: Result<_, Error> = try_block!({
+        let archive_name = tools::utf8_c_string(archive_name)?
+            .ok_or_else(|| format_err!("archive_name must not be NULL"))?;
+
+        conn.open_image(archive_name, cache_chunks)
+    });
+
+    match result {
+        Ok(aid) => aid,
+        Err(err) => raise_error_int!(error, err),
+    }
+}
+
+/// Read `size` bytes at `offset` from an image opened with
+/// `proxmox_restore_open_image`.
+///
+/// Chunks covering the requested range are faulted in (and cached) on
+/// demand, so callers can use this as a random-access block backend
+/// instead of materializing the whole image up front. Returns the
+/// number of bytes copied into `buf`, or `-1` on error.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn proxmox_restore_read_image_at(
+    handle: *mut ProxmoxRestoreHandle,
+    aid: c_int,
+    buf: *mut c_uchar,
+    offset: u64,
+    size: u64,
+    error: * mut * mut c_char,
+) -> c_int {
+
+    let conn = unsafe { &mut *(handle as * mut ProxmoxRestore) };
+
+    let result: Result<_, Error> = try_block!({
+        let buf = unsafe { std::slice::from_raw_parts_mut(buf, size as usize) };
+        conn.read_image_at(aid, buf, offset, size)
+    });
+
+    match result {
+        Ok(len) => len as c_int,
+        Err(err) => raise_error_int!(error, err),
+    }
+}
+
+/// Restore an image (async)
+///
+/// Like `proxmox_restore_image`, but the download runs on the restore
+/// connection's own runtime instead of blocking the calling thread.
+/// `data_callback` is invoked for each chunk (or sparse run) as it
+/// arrives, exactly as in the sync variant; `done_callback` fires once
+/// the whole image has been restored, or on error, and can be aborted
+/// with `proxmox_restore_abort`.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn proxmox_restore_image_async(
+    handle: *mut ProxmoxRestoreHandle,
+    archive_name: *const c_char, // expect full name here, i.e. "name.img.fidx"
+    data_callback: extern "C" fn(*mut c_void, u64, *const c_uchar, u64) -> c_int,
+    data_callback_data: *mut c_void,
+    done_callback: extern "C" fn(*mut c_void),
+    done_callback_data: *mut c_void,
+    result: *mut c_int,
+    error: * mut * mut c_char,
+    verbose: bool,
+) {
+    let conn = unsafe { &mut *(handle as * mut ProxmoxRestore) };
+    let callback_info = CallbackPointers { callback: done_callback, callback_data: done_callback_data, error, result };
+
+    let archive_name = match tools::utf8_c_string(archive_name) {
+        Ok(Some(name)) => name,
+        Ok(None) => {
+            callback_info.send_result(Err(format_err!("archive_name must not be NULL")));
+            return;
+        }
+        Err(err) => {
+            callback_info.send_result(Err(err));
+            return;
+        }
+    };
+
+    let write_data_callback = move |offset: u64, data: &[u8]| {
+        data_callback(data_callback_data, offset, data.as_ptr(), data.len() as u64)
+    };
+
+    let write_zero_callback = move |offset: u64, len: u64| {
+        data_callback(data_callback_data, offset, std::ptr::null(), len)
+    };
+
+    conn.runtime().spawn(async move {
+        let result = conn.restore_image(archive_name, write_data_callback, write_zero_callback, verbose)
+            .await
+            .map(|()| 0);
+        callback_info.send_result(result);
+    });
+}
+
+/// Get the backup manifest
+///
+/// Downloads and verifies the backup manifest and returns it as a JSON
+/// string (archive names, sizes, crypt mode, and the backup
+/// fingerprint), so callers can list available disks and pre-size block
+/// devices before opening any archive. Free the result with
+/// proxmox_backup_free_error().
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn proxmox_restore_get_manifest(
+    handle: *mut ProxmoxRestoreHandle,
+    error: * mut * mut c_char,
+) -> *mut c_char {
+    let conn = unsafe { &mut *(handle as * mut ProxmoxRestore) };
+
+    match conn.get_manifest() {
+        Ok(manifest) => match CString::new(manifest) {
+            Ok(manifest) => manifest.into_raw(),
+            Err(err) => raise_error_null!(error, err),
+        },
+        Err(err) => raise_error_null!(error, err),
+    }
+}
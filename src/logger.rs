@@ -0,0 +1,74 @@
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::Once;
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+static INSTALL_ONCE: Once = Once::new();
+
+/// Wraps the C callback pointer so it can be stored in a `tracing`
+/// `Layer`, which requires `Send + Sync`.
+///
+/// The caller is responsible for the callback being safe to invoke from
+/// any worker thread, since events are forwarded from whichever thread
+/// produced them.
+struct LogCallback {
+    callback: extern "C" fn(c_int, *const c_char, *mut c_void),
+    data: usize, // *mut c_void is not Sync; store as usize and cast back on use
+}
+unsafe impl Send for LogCallback {}
+unsafe impl Sync for LogCallback {}
+
+impl<S: Subscriber> Layer<S> for LogCallback {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let level = match *event.metadata().level() {
+            Level::ERROR => 0,
+            Level::WARN => 1,
+            Level::INFO => 2,
+            Level::DEBUG => 3,
+            Level::TRACE => 4,
+        };
+
+        let mut message = MessageVisitor(String::new());
+        event.record(&mut message);
+
+        let msg = match CString::new(message.0) {
+            Ok(msg) => msg,
+            Err(_) => return, // message contained an embedded NUL, drop it
+        };
+
+        (self.callback)(level, msg.as_ptr(), self.data as *mut c_void);
+    }
+}
+
+/// Collects the `message` field of a `tracing` event into a plain
+/// `String` for the C callback.
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+/// Install a `tracing` layer that forwards every event emitted by this
+/// crate (and the underlying `pbs-client` code) to `callback`.
+///
+/// Only the first call takes effect; later calls are ignored, since a
+/// process-global subscriber can only be installed once.
+pub(crate) fn set_log_callback(
+    callback: extern "C" fn(c_int, *const c_char, *mut c_void),
+    data: *mut c_void,
+) {
+    INSTALL_ONCE.call_once(|| {
+        use tracing_subscriber::prelude::*;
+
+        let layer = LogCallback { callback, data: data as usize };
+
+        let _ = tracing_subscriber::registry().with(layer).try_init();
+    });
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, format_err, Error};
+use lru::LruCache;
+
+use proxmox_backup::backup::{BackupReader, FixedIndexReader, IndexFile};
+use proxmox_backup::client::HttpClient;
+
+use crate::BackupSetup;
+
+/// Chunks kept in memory per open image when the caller does not request
+/// a specific cache size.
+const DEFAULT_CHUNK_CACHE_SIZE: usize = 256;
+
+/// One archive opened for random-access reads via `read_image_at`.
+struct OpenImage {
+    index: FixedIndexReader,
+    chunk_size: u64,
+    cache: Mutex<LruCache<[u8; 32], Arc<Vec<u8>>>>,
+}
+
+/// Drives one restore job: connects to the backup server and streams
+/// image archives back to the caller.
+pub(crate) struct ProxmoxRestore {
+    setup: BackupSetup,
+    runtime: tokio::runtime::Runtime,
+    client: Mutex<Option<Arc<BackupReader>>>,
+    images: Mutex<HashMap<i32, Arc<OpenImage>>>,
+    next_aid: Mutex<i32>,
+    aborted: Arc<Mutex<Option<String>>>,
+}
+
+impl ProxmoxRestore {
+    pub fn new(setup: BackupSetup) -> Result<Self, Error> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+
+        Ok(Self {
+            setup,
+            runtime,
+            client: Mutex::new(None),
+            images: Mutex::new(HashMap::new()),
+            next_aid: Mutex::new(0),
+            aborted: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    pub fn runtime(&self) -> &tokio::runtime::Runtime {
+        &self.runtime
+    }
+
+    fn check_aborted(&self) -> Result<(), Error> {
+        if let Some(reason) = self.aborted.lock().unwrap().as_ref() {
+            bail!("restore aborted: {}", reason);
+        }
+        Ok(())
+    }
+
+    /// Connect to the backup server and open the requested snapshot.
+    pub async fn connect(&self) -> Result<c_int, Error> {
+        self.check_aborted()?;
+
+        let http_client = HttpClient::new(
+            &self.setup.host,
+            &self.setup.user,
+            self.setup.fingerprint.clone(),
+        )?;
+
+        let client = BackupReader::start(
+            http_client,
+            &self.setup.store,
+            &self.setup.backup_id,
+            self.setup.backup_time,
+            true,
+        ).await?;
+
+        *self.client.lock().unwrap() = Some(Arc::new(client));
+
+        Ok(0)
+    }
+
+    fn client(&self) -> Result<Arc<BackupReader>, Error> {
+        self.client.lock().unwrap().clone()
+            .ok_or_else(|| format_err!("not connected"))
+    }
+
+    /// Download and verify the backup manifest, and return it as a JSON
+    /// string (archive names, sizes, crypt mode, and the manifest's own
+    /// encryption-key fingerprint) so callers can discover what's in the
+    /// snapshot and validate its key before opening any archive. This is
+    /// the backup's crypt fingerprint, not the server TLS fingerprint
+    /// already passed into `proxmox_restore_connect(_async)`.
+    pub fn get_manifest(&self) -> Result<String, Error> {
+        let manifest = self.runtime.block_on(self.client()?.download_manifest())?;
+
+        let archives: Vec<serde_json::Value> = manifest.files().iter().map(|entry| {
+            serde_json::json!({
+                "filename": entry.filename,
+                "size": entry.size,
+                "crypt-mode": entry.crypt_mode,
+            })
+        }).collect();
+
+        let manifest_json = serde_json::json!({
+            "backup-id": self.setup.backup_id,
+            "backup-time": self.setup.backup_time.timestamp(),
+            "fingerprint": manifest.fingerprint().map(|f| f.to_string()),
+            "archives": archives,
+        });
+
+        Ok(manifest_json.to_string())
+    }
+
+    /// Stream `archive_name` front-to-back, calling `write_data` for
+    /// chunk data and `write_zero` for sparse regions.
+    pub fn restore<W, Z>(
+        &self,
+        archive_name: String,
+        write_data: W,
+        write_zero: Z,
+        verbose: bool,
+    ) -> Result<(), Error>
+    where
+        W: Fn(u64, &[u8]) -> i32 + Send + 'static,
+        Z: Fn(u64, u64) -> i32 + Send + 'static,
+    {
+        self.runtime.block_on(self.restore_image(archive_name, write_data, write_zero, verbose))
+    }
+
+    /// Async core of `restore`, also used by the `_async` C entry point
+    /// so it can be spawned and cancelled like a `BackupTask`.
+    ///
+    /// A single image restore is one long `BackupReader::restore_image`
+    /// call, so `check_aborted()` alone (consulted once, up front) would
+    /// only notice an abort *between* images. Instead every `write_data`/
+    /// `write_zero` invocation re-checks the abort flag and returns an
+    /// error to the reader, which bails out after the current chunk —
+    /// giving abort roughly one chunk's worth of latency, the same
+    /// granularity `BackupTask` gets from its per-chunk spawned tasks.
+    pub async fn restore_image<W, Z>(
+        &self,
+        archive_name: String,
+        write_data: W,
+        write_zero: Z,
+        verbose: bool,
+    ) -> Result<(), Error>
+    where
+        W: Fn(u64, &[u8]) -> i32 + Send + 'static,
+        Z: Fn(u64, u64) -> i32 + Send + 'static,
+    {
+        self.check_aborted()?;
+
+        let aborted = self.aborted.clone();
+        let write_data = move |offset: u64, data: &[u8]| {
+            if aborted.lock().unwrap().is_some() {
+                return -1;
+            }
+            write_data(offset, data)
+        };
+
+        let aborted = self.aborted.clone();
+        let write_zero = move |offset: u64, len: u64| {
+            if aborted.lock().unwrap().is_some() {
+                return -1;
+            }
+            write_zero(offset, len)
+        };
+
+        self.client()?
+            .restore_image(&archive_name, write_data, write_zero, verbose)
+            .await
+    }
+
+    /// Abort the in-flight restore, mirroring `proxmox_backup_abort`.
+    ///
+    /// This is cooperative, like `BackupTask::abort`: it does not force
+    /// the in-flight future to stop, it just flips a flag that
+    /// `check_aborted()` observes at the next call, so a task that has
+    /// already started still runs `send_result` and delivers its
+    /// completion callback. Further calls fail immediately; the caller
+    /// must still disconnect to free the handle.
+    pub fn abort(&self, reason: String) {
+        *self.aborted.lock().unwrap() = Some(reason);
+    }
+
+    /// Open `archive_name` for random-access reads and return an opaque
+    /// `aid` to pass to `read_image_at`.
+    ///
+    /// `cache_chunks` bounds the in-memory LRU chunk cache kept for this
+    /// image; pass `0` to use [`DEFAULT_CHUNK_CACHE_SIZE`].
+    pub fn open_image(&self, archive_name: String, cache_chunks: u64) -> Result<i32, Error> {
+        let index = self.runtime.block_on(
+            self.client()?.download_fixed_index(&archive_name),
+        )?;
+
+        let chunk_size = index.chunk_size();
+
+        let cache_size = if cache_chunks > 0 {
+            cache_chunks as usize
+        } else {
+            DEFAULT_CHUNK_CACHE_SIZE
+        };
+
+        let open_image = Arc::new(OpenImage {
+            index,
+            chunk_size,
+            cache: Mutex::new(LruCache::new(cache_size)),
+        });
+
+        let mut next_aid = self.next_aid.lock().unwrap();
+        let aid = *next_aid;
+        *next_aid += 1;
+
+        self.images.lock().unwrap().insert(aid, open_image);
+
+        Ok(aid)
+    }
+
+    /// Read `size` bytes at `offset` from the image opened as `aid`,
+    /// faulting in and caching any chunks not already held in memory.
+    ///
+    /// Returns the number of bytes copied into `buf`: `size`, or less
+    /// where the range runs past the end of the image.
+    pub fn read_image_at(&self, aid: i32, buf: &mut [u8], offset: u64, size: u64) -> Result<u64, Error> {
+        if size == 0 {
+            return Ok(0);
+        }
+
+        // Clone the image handle and drop the `images` lock before any
+        // chunk fetch, so a slow network round-trip for one image never
+        // blocks concurrent reads of another.
+        let image = self.images.lock().unwrap().get(&aid)
+            .cloned()
+            .ok_or_else(|| format_err!("read_image_at: no image open for aid {}", aid))?;
+
+        let chunk_size = image.chunk_size;
+        let first_chunk = offset / chunk_size;
+        let last_chunk = (offset + size - 1) / chunk_size;
+
+        let mut copied = 0u64;
+        for chunk_idx in first_chunk..=last_chunk {
+            let digest = *image.index.index_digest(chunk_idx as usize)
+                .ok_or_else(|| format_err!("read_image_at: chunk {} out of range", chunk_idx))?;
+
+            let chunk_start = chunk_idx * chunk_size;
+            let chunk_end = (chunk_start + chunk_size).min(image.index.index_bytes());
+
+            let copy_start = chunk_start.max(offset);
+            let copy_end = chunk_end.min(offset + size);
+            if copy_start >= copy_end {
+                continue;
+            }
+
+            let buf_offset = (copy_start - offset) as usize;
+            let chunk_offset = (copy_start - chunk_start) as usize;
+            let len = (copy_end - copy_start) as usize;
+            copied += len as u64;
+
+            if digest == *image.index.zero_chunk_digest() {
+                for byte in &mut buf[buf_offset..buf_offset + len] {
+                    *byte = 0;
+                }
+                continue;
+            }
+
+            let data = self.load_chunk(&image, &digest)?;
+            buf[buf_offset..buf_offset + len]
+                .copy_from_slice(&data[chunk_offset..chunk_offset + len]);
+        }
+
+        Ok(copied)
+    }
+
+    /// Fetch a chunk's decrypted data, serving it from the image's LRU
+    /// cache when possible to avoid a server round-trip.
+    fn load_chunk(&self, image: &OpenImage, digest: &[u8; 32]) -> Result<Arc<Vec<u8>>, Error> {
+        if let Some(data) = image.cache.lock().unwrap().get(digest) {
+            return Ok(data.clone());
+        }
+
+        let data = Arc::new(self.runtime.block_on(
+            self.client()?.download_decrypted_chunk(digest),
+        )?);
+
+        image.cache.lock().unwrap().put(*digest, data.clone());
+
+        Ok(data)
+    }
+}
@@ -0,0 +1,35 @@
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+use anyhow::Error;
+
+/// Convert a (possibly NULL) C string into an owned UTF8 `String`.
+///
+/// Returns `Ok(None)` if `ptr` is NULL, and an error if the data is not
+/// valid UTF8.
+pub(crate) fn utf8_c_string(ptr: *const c_char) -> Result<Option<String>, Error> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    let c_str = unsafe { CStr::from_ptr(ptr) };
+    Ok(Some(c_str.to_str()?.to_owned()))
+}
+
+/// Like [`utf8_c_string`], but replaces invalid UTF8 sequences instead of
+/// returning an error.
+pub(crate) fn utf8_c_string_lossy(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+    let c_str = unsafe { CStr::from_ptr(ptr) };
+    Some(c_str.to_string_lossy().into_owned())
+}
+
+/// Like [`utf8_c_string_lossy`], but for arguments that must not be NULL.
+///
+/// # Safety
+///
+/// `ptr` must point to a valid, NUL-terminated C string.
+pub(crate) unsafe fn utf8_c_string_lossy_non_null(ptr: *const c_char) -> String {
+    utf8_c_string_lossy(ptr).expect("unexpected NULL pointer")
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+
+use anyhow::Error;
+use tokio::sync::Mutex;
+
+use proxmox_backup::backup::{BackupWriter, DataChunkBuilder, FixedIndexReader, FixedIndexWriter, IndexFile};
+
+use crate::commands::ImageCommand;
+
+/// Per-image chunking and upload state.
+///
+/// One of these is created per registered image and shared (via `clone`)
+/// between the `write_data` and `close_image` calls for its `dev_id`.
+#[derive(Clone)]
+pub(crate) struct UploadQueue {
+    inner: Arc<Mutex<ImageUploadState>>,
+}
+
+pub(crate) struct ImageUploadState {
+    writer: Arc<BackupWriter>,
+    device_name: String,
+    chunk_size: u64,
+    incremental: bool,
+    index: FixedIndexWriter,
+    /// The previous backup's index for this device, when this is an
+    /// incremental image and the server knew about one. Used to fold
+    /// unchanged chunks into "reuse" instead of re-uploading them.
+    previous_index: Option<FixedIndexReader>,
+}
+
+impl UploadQueue {
+    pub async fn new(
+        writer: Arc<BackupWriter>,
+        device_name: String,
+        size: u64,
+        chunk_size: u64,
+        incremental: bool,
+        previous_index: Option<FixedIndexReader>,
+    ) -> Result<Self, Error> {
+        let index = writer
+            .create_image(&format!("{}.img.fidx", device_name), size, chunk_size)
+            .await?;
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(ImageUploadState {
+                writer,
+                device_name,
+                chunk_size,
+                incremental,
+                index,
+                previous_index,
+            })),
+        })
+    }
+
+    /// Chunk, (maybe) upload, and append one write to the image index.
+    ///
+    /// If this is an incremental image and `data`'s digest matches the
+    /// chunk already stored at this offset in the previous backup, the
+    /// chunk is folded in as a reference instead of being re-uploaded.
+    pub async fn write(&self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let command = if data.iter().all(|&b| b == 0) {
+            ImageCommand::AppendZero { offset, size: data.len() as u64 }
+        } else {
+            let digest = *DataChunkBuilder::new(data).build()?.digest();
+
+            let mut state = self.inner.lock().await;
+            if state.is_known_chunk(offset, &digest) {
+                ImageCommand::KnownChunk { offset, digest }
+            } else {
+                let writer = state.writer.clone();
+                drop(state);
+                writer.upload_chunk(&digest, data).await?;
+                ImageCommand::AppendChunk { offset, digest, data: data.to_vec() }
+            }
+        };
+
+        self.inner.lock().await.append(command)
+    }
+
+    pub async fn close(&self) -> Result<(), Error> {
+        self.inner.lock().await.index.close()?;
+        Ok(())
+    }
+
+    /// Digests of every chunk in the previous backup's image, in offset
+    /// order, for `proxmox_backup_get_known_chunks`.
+    pub async fn known_chunks(&self) -> Vec<[u8; 32]> {
+        let state = self.inner.lock().await;
+        match &state.previous_index {
+            Some(index) => (0..index.index_count())
+                .map(|i| *index.index_digest(i).expect("index_count bounds digest"))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl ImageUploadState {
+    /// Does the previous backup already hold this exact chunk at this
+    /// offset?
+    fn is_known_chunk(&self, offset: u64, digest: &[u8; 32]) -> bool {
+        if !self.incremental {
+            return false;
+        }
+        let previous_index = match &self.previous_index {
+            Some(index) => index,
+            None => return false,
+        };
+        let chunk_idx = (offset / self.chunk_size) as usize;
+        previous_index.index_digest(chunk_idx) == Some(digest)
+    }
+
+    fn append(&mut self, command: ImageCommand) -> Result<(), Error> {
+        match command {
+            ImageCommand::AppendChunk { offset, digest, .. } => {
+                self.index.add_digest(offset, &digest)?;
+            }
+            ImageCommand::AppendZero { offset, .. } => {
+                self.index.add_digest(offset, self.index.zero_chunk_digest())?;
+            }
+            ImageCommand::KnownChunk { offset, digest } => {
+                self.index.add_digest(offset, &digest)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, format_err, Error};
+
+use proxmox_backup::backup::BackupManifest;
+use proxmox_backup::client::{BackupWriter, HttpClient};
+
+use crate::capi_types::DataPointer;
+use crate::upload_queue::UploadQueue;
+use crate::BackupSetup;
+
+/// Drives one backup job: connects to the server, registers image
+/// archives and config blobs, and uploads their data.
+///
+/// All calls are dispatched onto `runtime` so the C API can offer both a
+/// blocking and an `_async` variant for every operation.
+pub(crate) struct BackupTask {
+    setup: BackupSetup,
+    runtime: tokio::runtime::Runtime,
+    client: Mutex<Option<Arc<BackupWriter>>>,
+    previous_manifest: Mutex<Option<Arc<BackupManifest>>>,
+    images: Mutex<HashMap<u8, UploadQueue>>,
+    next_dev_id: Mutex<u8>,
+    aborted: Mutex<Option<String>>,
+}
+
+impl BackupTask {
+    pub fn new(setup: BackupSetup) -> Result<Self, Error> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+
+        Ok(Self {
+            setup,
+            runtime,
+            client: Mutex::new(None),
+            previous_manifest: Mutex::new(None),
+            images: Mutex::new(HashMap::new()),
+            next_dev_id: Mutex::new(0),
+            aborted: Mutex::new(None),
+        })
+    }
+
+    pub fn runtime(&self) -> &tokio::runtime::Runtime {
+        &self.runtime
+    }
+
+    fn check_aborted(&self) -> Result<(), Error> {
+        if let Some(reason) = self.aborted.lock().unwrap().as_ref() {
+            bail!("backup task aborted: {}", reason);
+        }
+        Ok(())
+    }
+
+    /// Connect to the backup server.
+    ///
+    /// Returns `1` if a previous backup of this guest was found (so the
+    /// caller can reuse its manifest for incremental backups), `0`
+    /// otherwise.
+    pub async fn connect(&self) -> Result<c_int, Error> {
+        self.check_aborted()?;
+
+        let client = HttpClient::new(
+            &self.setup.host,
+            &self.setup.user,
+            self.setup.fingerprint.clone(),
+        )?;
+
+        let writer = BackupWriter::start(
+            client,
+            &self.setup.store,
+            &self.setup.backup_id,
+            self.setup.backup_time,
+            false,
+        ).await?;
+
+        let found_previous = writer.previous_backup_time().is_some();
+
+        if let Ok(manifest) = writer.download_previous_manifest().await {
+            *self.previous_manifest.lock().unwrap() = Some(Arc::new(manifest));
+        }
+
+        *self.client.lock().unwrap() = Some(Arc::new(writer));
+
+        Ok(if found_previous { 1 } else { 0 })
+    }
+
+    fn writer(&self) -> Result<Arc<BackupWriter>, Error> {
+        self.client.lock().unwrap().clone()
+            .ok_or_else(|| format_err!("not connected"))
+    }
+
+    pub async fn register_image(
+        &self,
+        device_name: String,
+        size: u64,
+        incremental: bool,
+    ) -> Result<c_int, Error> {
+        self.check_aborted()?;
+
+        let writer = self.writer()?;
+
+        let previous_index = if incremental {
+            let previous_manifest = self.previous_manifest.lock().unwrap().clone();
+            match previous_manifest {
+                Some(_) => match writer
+                    .download_previous_fixed_index(&format!("{}.img.fidx", device_name))
+                    .await
+                {
+                    Ok(index) => Some(index),
+                    Err(err) => {
+                        tracing::warn!(
+                            "register_image: could not fetch previous index for '{}', \
+                             falling back to a full upload: {}",
+                            device_name, err,
+                        );
+                        None
+                    }
+                },
+                None => None,
+            }
+        } else {
+            None
+        };
+
+        let upload = UploadQueue::new(
+            writer,
+            device_name,
+            size,
+            self.setup.chunk_size,
+            incremental,
+            previous_index,
+        ).await?;
+
+        let mut dev_id = self.next_dev_id.lock().unwrap();
+        let id = *dev_id;
+        *dev_id += 1;
+
+        self.images.lock().unwrap().insert(id, upload);
+
+        Ok(id as c_int)
+    }
+
+    /// Digests of every chunk the previous backup's image for `dev_id`
+    /// already holds, so the caller can skip re-uploading unchanged
+    /// chunks on top of its own dirty-bitmap tracking.
+    pub async fn get_known_chunks(&self, dev_id: u8) -> Result<Vec<[u8; 32]>, Error> {
+        self.check_aborted()?;
+
+        let upload = self.images.lock().unwrap().get(&dev_id)
+            .ok_or_else(|| format_err!("get_known_chunks: dev_id {} not registered", dev_id))?
+            .clone();
+
+        Ok(upload.known_chunks().await)
+    }
+
+    pub async fn add_config(
+        &self,
+        name: String,
+        data: DataPointer,
+        size: u64,
+    ) -> Result<c_int, Error> {
+        self.check_aborted()?;
+
+        let data = unsafe { std::slice::from_raw_parts(data.0, size as usize) };
+        let writer = self.writer()?;
+        writer.upload_blob_from_data(data.to_vec(), &format!("{}.blob", name), true).await?;
+
+        Ok(0)
+    }
+
+    pub async fn write_data(
+        &self,
+        dev_id: u8,
+        data: DataPointer,
+        offset: u64,
+        size: u64,
+    ) -> Result<c_int, Error> {
+        self.check_aborted()?;
+
+        let data = unsafe { std::slice::from_raw_parts(data.0, size as usize) };
+
+        let upload = self.images.lock().unwrap().get(&dev_id)
+            .ok_or_else(|| format_err!("write_data: dev_id {} not registered", dev_id))?
+            .clone();
+
+        upload.write(offset, data).await?;
+
+        Ok(size as c_int)
+    }
+
+    pub async fn close_image(&self, dev_id: u8) -> Result<c_int, Error> {
+        self.check_aborted()?;
+
+        let upload = self.images.lock().unwrap().remove(&dev_id)
+            .ok_or_else(|| format_err!("close_image: dev_id {} not registered", dev_id))?;
+
+        upload.close().await?;
+
+        Ok(0)
+    }
+
+    pub async fn finish(&self) -> Result<c_int, Error> {
+        self.check_aborted()?;
+
+        let writer = self.writer()?;
+        writer.finish().await?;
+
+        Ok(0)
+    }
+
+    pub fn abort(&self, reason: String) {
+        *self.aborted.lock().unwrap() = Some(reason);
+    }
+}